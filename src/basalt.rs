@@ -0,0 +1,444 @@
+//! Basalt-style random peer sampling for membership that scales past the
+//! handful of repos full-mesh connectivity ([`crate::peering::PeeringManager`])
+//! can handle.
+//!
+//! Instead of connecting to every known peer, each node keeps a bounded
+//! view of at most `VIEW_SLOTS` (2 * 32) peer identities and periodically gossips a
+//! random subset of that view with a randomly chosen peer from it, merging
+//! the results back in. The anti-poisoning mechanism is per-slot: each slot
+//! is tied to one of a set of independent hash seeds, and among the
+//! candidates competing for a slot only the one whose `hash(seed, peer_id)`
+//! is smallest is kept. Crucially, those seeds are derived from a secret
+//! generated locally when the node's view is created, not from the slot
+//! index alone: if every node used the same public seeds, an attacker could
+//! precompute offline, for each seed, a `RepoId` that minimizes its hash and
+//! have that one identity win the same slot on every node in the network
+//! simultaneously. With node-local seeds, an attacker still can't predict
+//! which (if any) slots their flooded identities will win on any given
+//! node, bounding how much of any one node's view they can occupy.
+//!
+//! [`register_basalt`] attaches a repo's `Basalt` view to this module's
+//! per-`RepoId` registry; [`crate::network_connect`] then registers each
+//! connection against it via [`BasaltConnection`] so `Message::BasaltGossip`
+//! requests and replies are actually exchanged over the wire, and
+//! [`run_gossip_round`] is what a caller's periodic timer should drive to
+//! sample a partner and kick off one round of the exchange described above.
+use crate::interfaces::{Message, RepoId};
+use crate::peering::PeeringManager;
+use parking_lot::Mutex;
+use rand::seq::IteratorRandom;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::mpsc;
+
+/// Number of independent hash-seeded slots in the view. The view holds up
+/// to `VIEW_SLOTS` peers; each slot keeps the single candidate that wins
+/// its seed's hash lottery.
+const VIEW_SLOTS: usize = 2 * 32;
+
+/// How many peers are exchanged with a sampled partner on each gossip
+/// round.
+const GOSSIP_SAMPLE_SIZE: usize = 8;
+
+/// One peer identity and the address to dial it at, as carried around the
+/// membership protocol.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PeerDescriptor {
+    pub repo_id: RepoId,
+    pub addr: SocketAddr,
+}
+
+/// Deterministically scores `peer` against slot `seed`: the candidate with
+/// the smallest score wins the slot. `seed` is derived from this node's
+/// private per-view secret (see [`Basalt::new`]), so a node's slot seeds
+/// can't be predicted or precomputed against by an outside attacker, and a
+/// flood of attacker-controlled IDs can only ever win the slots where one
+/// of those IDs happens to score lowest on *this* node, not the whole
+/// network at once.
+fn slot_score(seed: u64, peer: &RepoId) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    peer.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Derives slot `i`'s seed from this node's private view secret. Mixing in
+/// the secret (rather than using the slot index alone) is what makes the
+/// seeds unpredictable to an outside observer.
+fn derive_slot_seed(view_secret: u64, i: usize) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    view_secret.hash(&mut hasher);
+    i.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A single view slot: the peer currently occupying it (if any) and the
+/// node-local hash seed used to arbitrate who gets to occupy it.
+struct Slot {
+    seed: u64,
+    occupant: Option<(PeerDescriptor, u64)>,
+}
+
+/// The bounded, continuously-refreshed, uniform-random sample of mesh
+/// membership this node currently knows about.
+pub struct Basalt {
+    local: PeerDescriptor,
+    slots: Vec<Slot>,
+}
+
+impl Basalt {
+    /// Start a fresh view seeded only with `bootstrap` peers. `local` is
+    /// this node's own identity and is never placed in its own view. A
+    /// fresh, private view secret is generated and mixed into every slot's
+    /// seed so this node's slot lottery can't be predicted by an outside
+    /// attacker (see the module and [`slot_score`] docs).
+    pub fn new(local: PeerDescriptor, bootstrap: Vec<PeerDescriptor>) -> Self {
+        let view_secret: u64 = rand::random();
+        let slots = (0..VIEW_SLOTS)
+            .map(|i| Slot {
+                seed: derive_slot_seed(view_secret, i),
+                occupant: None,
+            })
+            .collect();
+        let mut basalt = Basalt { local, slots };
+        for peer in bootstrap {
+            basalt.offer(peer);
+        }
+        basalt
+    }
+
+    /// Offer a candidate peer to the view. The candidate is kept only if it
+    /// wins at least one slot's hash lottery against whatever currently
+    /// occupies that slot.
+    pub fn offer(&mut self, peer: PeerDescriptor) {
+        if peer.repo_id == self.local.repo_id {
+            return;
+        }
+        for slot in &mut self.slots {
+            let score = slot_score(slot.seed, &peer.repo_id);
+            let beats_occupant = match &slot.occupant {
+                Some((_, occupant_score)) => score < *occupant_score,
+                None => true,
+            };
+            if beats_occupant {
+                slot.occupant = Some((peer.clone(), score));
+            }
+        }
+    }
+
+    /// The current sampled view: the distinct set of peers occupying at
+    /// least one slot.
+    pub fn view(&self) -> Vec<PeerDescriptor> {
+        let mut seen = HashMap::new();
+        for slot in &self.slots {
+            if let Some((peer, _)) = &slot.occupant {
+                seen.entry(peer.repo_id.clone()).or_insert_with(|| peer.clone());
+            }
+        }
+        seen.into_values().collect()
+    }
+
+    /// Pick a peer from the view at random to gossip with this round, and
+    /// the random subset of our own view to send them.
+    pub fn sample_for_gossip(&self) -> Option<(PeerDescriptor, Vec<PeerDescriptor>)> {
+        let view = self.view();
+        let mut rng = rand::thread_rng();
+        let partner = view.iter().choose(&mut rng)?.clone();
+        let sample = view
+            .into_iter()
+            .filter(|p| p.repo_id != partner.repo_id)
+            .choose_multiple(&mut rng, GOSSIP_SAMPLE_SIZE);
+        Some((partner, sample))
+    }
+
+    /// Merge a peer set received from a gossip partner into the view,
+    /// re-running the hash lottery for each candidate. Called by
+    /// [`BasaltConnection::handle_incoming`] for every incoming gossip
+    /// request and reply. Merging only adds candidates: a peer already in
+    /// the view keeps its slots until another candidate beats it there.
+    pub fn merge(&mut self, peers: impl IntoIterator<Item = PeerDescriptor>) {
+        for peer in peers {
+            self.offer(peer);
+        }
+    }
+
+    /// Point `peering` at exactly the peers currently in this view,
+    /// removing anyone sampled out from its desired set (which stops their
+    /// redial loops, see [`PeeringManager::remove_peer`]) and dialing anyone
+    /// newly sampled in. Call this after every gossip round or churn event so
+    /// sync connections track the view rather than connecting to everyone
+    /// this node has ever heard of.
+    pub fn sync_view_to_peering(&self, peering: &Arc<PeeringManager>) {
+        let view = self.view();
+        let desired: std::collections::HashSet<SocketAddr> = view.iter().map(|p| p.addr).collect();
+        for (addr, _) in peering.peer_states() {
+            if !desired.contains(&addr) {
+                peering.remove_peer(addr);
+            }
+        }
+        for peer in view {
+            peering.add_peer(peer.addr);
+        }
+    }
+}
+
+/// Per-`RepoId` shared state backing the peer-sampling gossip exchange: the
+/// live view itself, and the set of connections currently able to carry a
+/// directed `Message::BasaltGossip` to a given peer.
+struct BasaltHub {
+    basalt: Mutex<Basalt>,
+    connections: Mutex<HashMap<RepoId, mpsc::UnboundedSender<Message>>>,
+}
+
+fn hub_registry() -> &'static Mutex<HashMap<RepoId, Arc<BasaltHub>>> {
+    static HUBS: OnceLock<Mutex<HashMap<RepoId, Arc<BasaltHub>>>> = OnceLock::new();
+    HUBS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn hub_for(repo_id: &RepoId) -> Option<Arc<BasaltHub>> {
+    hub_registry().lock().get(repo_id).cloned()
+}
+
+/// Attaches `basalt` to `repo_id` as the view [`BasaltConnection`] and
+/// [`run_gossip_round`] operate on. Call this once, after constructing a
+/// repo's [`Basalt`], before connecting to any peers — a repo with nothing
+/// registered here simply never participates in peer-sampling gossip (the
+/// existing bootstrap/full-mesh path in [`crate::peering::PeeringManager`]
+/// still works unaffected).
+pub fn register_basalt(repo_id: RepoId, basalt: Basalt) {
+    hub_registry().lock().insert(
+        repo_id,
+        Arc::new(BasaltHub {
+            basalt: Mutex::new(basalt),
+            connections: Mutex::new(HashMap::new()),
+        }),
+    );
+}
+
+/// One connection's registration for directed Basalt gossip traffic
+/// to/from `peer_id`. Dropping this deregisters the connection, so a later
+/// gossip round can't try to address a peer that's no longer connected.
+pub(crate) struct BasaltConnection {
+    repo_id: RepoId,
+    peer_id: RepoId,
+}
+
+impl BasaltConnection {
+    /// Registers a directed gossip channel to `peer_id` on `repo_id`'s view,
+    /// if one is registered (see [`register_basalt`]). Returns the guard and
+    /// a sender/receiver pair: send a `Message::BasaltGossip` reply through
+    /// the sender to have it forwarded out over this connection, and drain
+    /// the receiver into the same outgoing sink — it's also where
+    /// [`run_gossip_round`]-initiated requests addressed to `peer_id` arrive
+    /// from.
+    pub(crate) fn register(
+        repo_id: RepoId,
+        peer_id: RepoId,
+    ) -> (
+        Self,
+        mpsc::UnboundedSender<Message>,
+        mpsc::UnboundedReceiver<Message>,
+    ) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        if let Some(hub) = hub_for(&repo_id) {
+            hub.connections.lock().insert(peer_id.clone(), tx.clone());
+        }
+        (BasaltConnection { repo_id, peer_id }, tx, rx)
+    }
+
+    /// Handle one incoming `Message`. If it's a `Message::BasaltGossip`,
+    /// merge the sender's sample into our view and, for a request (not a
+    /// reply), return our own sample as the reply to send back — forward it
+    /// through the sender returned from [`Self::register`]. Returns `None`
+    /// for a reply (nothing further to send) or for any message that isn't
+    /// gossip traffic, so the caller passes those through unchanged.
+    pub(crate) fn handle_incoming(&self, msg: &Message) -> Option<Message> {
+        let Message::BasaltGossip { sample, reply } = msg else {
+            return None;
+        };
+        let hub = hub_for(&self.repo_id)?;
+        hub.basalt.lock().merge(sample.iter().cloned());
+        if *reply {
+            None
+        } else {
+            let our_sample = hub.basalt.lock().view();
+            Some(Message::BasaltGossip {
+                sample: our_sample,
+                reply: true,
+            })
+        }
+    }
+}
+
+impl Drop for BasaltConnection {
+    fn drop(&mut self) {
+        if let Some(hub) = hub_for(&self.repo_id) {
+            hub.connections.lock().remove(&self.peer_id);
+        }
+    }
+}
+
+/// Runs one round of peer-sampling gossip for `repo_id`'s registered view
+/// (see [`register_basalt`]): samples a partner and a subset of the view
+/// (see [`Basalt::sample_for_gossip`]) and sends it a `Message::BasaltGossip`
+/// request over its registered connection (see [`BasaltConnection`]).
+/// Returns `false` (a no-op) if no view is registered for `repo_id`, the
+/// view has no peers to sample, or there's no live connection to the
+/// sampled partner — callers are expected to invoke this on a periodic
+/// timer and tolerate occasional no-ops while the mesh is still forming.
+pub async fn run_gossip_round(repo_id: &RepoId) -> bool {
+    let Some(hub) = hub_for(repo_id) else {
+        return false;
+    };
+    let Some((partner, sample)) = hub.basalt.lock().sample_for_gossip() else {
+        return false;
+    };
+    let Some(sender) = hub.connections.lock().get(&partner.repo_id).cloned() else {
+        return false;
+    };
+    sender
+        .send(Message::BasaltGossip {
+            sample,
+            reply: false,
+        })
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(id: &str) -> PeerDescriptor {
+        PeerDescriptor {
+            repo_id: RepoId(id.to_string()),
+            addr: "127.0.0.1:1".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn slot_score_is_deterministic() {
+        let peer = RepoId("peer-a".to_string());
+        assert_eq!(slot_score(42, &peer), slot_score(42, &peer));
+    }
+
+    #[test]
+    fn slot_score_differs_across_seeds() {
+        // Not a formal collision-freedom proof, but the whole point of
+        // per-node seeds is that the same peer scores differently under
+        // different seeds; two arbitrary seeds should disagree.
+        let peer = RepoId("peer-a".to_string());
+        assert_ne!(slot_score(1, &peer), slot_score(2, &peer));
+    }
+
+    #[test]
+    fn slot_seeds_are_not_derivable_from_slot_index_alone() {
+        // This is the regression test for the anti-poisoning bug: two
+        // independently created views must not agree on their slot seeds,
+        // or an attacker could precompute winning ids offline against the
+        // (supposedly) fixed seed schedule.
+        let local = peer("local");
+        let a = Basalt::new(local.clone(), vec![]);
+        let b = Basalt::new(local, vec![]);
+        let seeds_a: Vec<u64> = a.slots.iter().map(|s| s.seed).collect();
+        let seeds_b: Vec<u64> = b.slots.iter().map(|s| s.seed).collect();
+        assert_ne!(seeds_a, seeds_b);
+    }
+
+    #[test]
+    fn offer_keeps_only_the_winning_candidate_per_slot() {
+        let mut basalt = Basalt::new(peer("local"), vec![]);
+        basalt.offer(peer("a"));
+        basalt.offer(peer("b"));
+        // Every occupied slot holds exactly the peer that won its lottery,
+        // i.e. whichever of the two offered peers scored lower for that
+        // slot's seed.
+        for slot in &basalt.slots {
+            if let Some((occupant, score)) = &slot.occupant {
+                let winner = [peer("a"), peer("b")]
+                    .into_iter()
+                    .min_by_key(|p| slot_score(slot.seed, &p.repo_id))
+                    .unwrap();
+                assert_eq!(occupant.repo_id, winner.repo_id);
+                assert_eq!(*score, slot_score(slot.seed, &occupant.repo_id));
+            }
+        }
+    }
+
+    #[test]
+    fn offer_never_admits_the_local_peer() {
+        let local = peer("local");
+        let mut basalt = Basalt::new(local.clone(), vec![]);
+        basalt.offer(local);
+        assert!(basalt.view().is_empty());
+    }
+
+    #[tokio::test]
+    async fn gossip_round_sends_a_request_to_a_registered_partner() {
+        let us = RepoId("gossip-round-us".to_string());
+        let partner = peer("gossip-round-partner");
+        register_basalt(us.clone(), Basalt::new(peer("gossip-round-us"), vec![partner.clone()]));
+        let (_conn, _tx, mut rx) = BasaltConnection::register(us.clone(), partner.repo_id.clone());
+
+        assert!(run_gossip_round(&us).await);
+
+        let msg = rx.try_recv().expect("gossip request should have been sent");
+        assert!(matches!(
+            msg,
+            Message::BasaltGossip { reply: false, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn gossip_round_is_a_no_op_without_a_live_connection_to_the_sampled_partner() {
+        let us = RepoId("gossip-round-disconnected-us".to_string());
+        register_basalt(
+            us.clone(),
+            Basalt::new(
+                peer("gossip-round-disconnected-us"),
+                vec![peer("gossip-round-disconnected-partner")],
+            ),
+        );
+        // No `BasaltConnection` registered for the sampled partner.
+        assert!(!run_gossip_round(&us).await);
+    }
+
+    #[test]
+    fn handle_incoming_request_merges_and_replies_in_kind() {
+        let us = RepoId("gossip-handle-us".to_string());
+        register_basalt(us.clone(), Basalt::new(peer("gossip-handle-us"), vec![]));
+        let peer_id = RepoId("gossip-handle-peer".to_string());
+        let (conn, _tx, _rx) = BasaltConnection::register(us.clone(), peer_id);
+
+        let request = Message::BasaltGossip {
+            sample: vec![peer("gossip-handle-newcomer")],
+            reply: false,
+        };
+        let response = conn.handle_incoming(&request);
+        assert!(matches!(response, Some(Message::BasaltGossip { reply: true, .. })));
+
+        let hub = hub_for(&us).unwrap();
+        let view = hub.basalt.lock().view();
+        assert!(view.iter().any(|p| p.repo_id.0 == "gossip-handle-newcomer"));
+    }
+
+    #[test]
+    fn handle_incoming_reply_merges_but_does_not_reply_again() {
+        let us = RepoId("gossip-handle-reply-us".to_string());
+        register_basalt(us.clone(), Basalt::new(peer("gossip-handle-reply-us"), vec![]));
+        let peer_id = RepoId("gossip-handle-reply-peer".to_string());
+        let (conn, _tx, _rx) = BasaltConnection::register(us.clone(), peer_id);
+
+        let reply = Message::BasaltGossip {
+            sample: vec![peer("gossip-handle-reply-newcomer")],
+            reply: true,
+        };
+        assert!(conn.handle_incoming(&reply).is_none());
+
+        let hub = hub_for(&us).unwrap();
+        let view = hub.basalt.lock().view();
+        assert!(view.iter().any(|p| p.repo_id.0 == "gossip-handle-reply-newcomer"));
+    }
+}
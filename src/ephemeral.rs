@@ -0,0 +1,393 @@
+//! Transient, non-persisted messages (presence, cursors, typing indicators)
+//! flood-routed across the mesh of connected repos.
+//!
+//! Unlike `RepoMessage::Sync`, an ephemeral message is never written to
+//! storage: it's forwarded to every other connected peer and then dropped.
+//! To avoid the message looping forever in a mesh, each sender stamps its
+//! own messages with a random per-process session id plus a monotonically
+//! increasing counter, and every repo tracks a small LRU of `(sender,
+//! session, count)` tuples it has already forwarded, dropping anything it's
+//! already seen. The session id (rather than the counter alone) means a
+//! restarted or reconnected repo doesn't collide with counts a peer already
+//! cached from its previous incarnation.
+//!
+//! Each `RepoHandle` has one [`EphemeralHub`] (looked up by [`RepoId`])
+//! shared by every connection registered against it via
+//! [`EphemeralConnection::register`]. A connection that receives a novel
+//! ephemeral message publishes it to local subscribers *and* re-broadcasts
+//! it through the hub to every other registered connection except the one
+//! it arrived on, which is what actually floods it across the mesh.
+//!
+//! The LRU dedup cache alone only bounds how much *history* a single repo
+//! remembers, not how many times a message can be re-broadcast in a mesh
+//! bigger than that history, so every message also carries a decrementing
+//! `hops_remaining` counter: each re-broadcast sends it on with one fewer
+//! hop, and a repo that receives a message with no hops left still delivers
+//! it to local subscribers but never re-broadcasts it further, independent
+//! of whatever the LRU currently holds or has evicted.
+use crate::interfaces::{DocumentId, RepoId, RepoMessage};
+use crate::repo::RepoHandle;
+use futures::{Stream, StreamExt};
+use lru::LruCache;
+use parking_lot::Mutex;
+use rand::Rng;
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::BroadcastStream;
+
+/// How many `(sender, session, count)` tuples each repo remembers before
+/// evicting the oldest. Sized for a handful of active gossipers, not for
+/// archival dedup.
+const SEEN_CACHE_SIZE: usize = 1024;
+
+/// The largest number of re-broadcast hops an ephemeral message is allowed
+/// before it's dropped instead of forwarded further, regardless of whether
+/// the LRU dedup cache still recognizes it. Generously sized for a mesh a
+/// few hops deeper than any full-mesh/peer-sampling topology this crate
+/// builds should ever produce, not tuned to a specific deployment's radius.
+const MAX_EPHEMERAL_HOPS: u8 = 16;
+
+/// Capacity of the local-subscriber broadcast channel; a slow subscriber
+/// that falls this far behind just misses the oldest events rather than
+/// blocking the mesh.
+const SUBSCRIBER_CHANNEL_CAPACITY: usize = 256;
+
+/// Identifies one ephemeral message for dedup purposes: the originating
+/// repo, that repo's current process session, and the per-session sequence
+/// number of this particular message.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct SeenKey {
+    sender: RepoId,
+    session: u64,
+    count: u64,
+}
+
+/// Assigns a random session id (once, per `RepoHandle`/process incarnation)
+/// and a monotonically increasing counter to outgoing ephemeral messages
+/// from this repo.
+pub(crate) struct EphemeralState {
+    session: u64,
+    next_count: AtomicU64,
+}
+
+impl Default for EphemeralState {
+    fn default() -> Self {
+        EphemeralState {
+            session: rand::thread_rng().gen(),
+            next_count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl EphemeralState {
+    fn next_stamp(&self) -> (u64, u64) {
+        (self.session, self.next_count.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Loop-prevention state shared by every connection on a repo: the set of
+/// `(sender, session, count)` tuples already seen, used to drop duplicates
+/// before they're re-broadcast.
+pub(crate) struct SeenTuples {
+    seen: Mutex<LruCache<SeenKey, ()>>,
+}
+
+impl Default for SeenTuples {
+    fn default() -> Self {
+        SeenTuples {
+            seen: Mutex::new(LruCache::new(
+                NonZeroUsize::new(SEEN_CACHE_SIZE).expect("SEEN_CACHE_SIZE is nonzero"),
+            )),
+        }
+    }
+}
+
+impl SeenTuples {
+    /// Returns `true` the first time this `(sender, session, count)` is
+    /// observed, and `false` (meaning: drop it, it's a duplicate or a loop)
+    /// on every subsequent observation.
+    fn observe(&self, sender: &RepoId, session: u64, count: u64) -> bool {
+        let key = SeenKey {
+            sender: sender.clone(),
+            session,
+            count,
+        };
+        let mut seen = self.seen.lock();
+        if seen.contains(&key) {
+            false
+        } else {
+            seen.put(key, ());
+            true
+        }
+    }
+}
+
+/// One ephemeral payload ready to hand to a subscriber.
+#[derive(Debug, Clone)]
+pub struct EphemeralEvent {
+    pub from_repo_id: RepoId,
+    pub document_id: DocumentId,
+    pub data: Vec<u8>,
+}
+
+/// Identifies one connection registered with a repo's [`EphemeralHub`], used
+/// to exclude the connection a message arrived on when re-broadcasting it.
+type ConnectionId = usize;
+
+/// Per-`RepoId` shared state: the set of connections currently registered,
+/// the dedup cache, the session stamping counter, and the channel local
+/// `subscribe_ephemeral` callers listen on.
+struct EphemeralHub {
+    state: EphemeralState,
+    seen: SeenTuples,
+    subscribers: broadcast::Sender<EphemeralEvent>,
+    connections: Mutex<HashMap<ConnectionId, mpsc::UnboundedSender<RepoMessage>>>,
+    next_connection_id: AtomicUsize,
+}
+
+impl EphemeralHub {
+    fn new() -> Self {
+        let (subscribers, _) = broadcast::channel(SUBSCRIBER_CHANNEL_CAPACITY);
+        EphemeralHub {
+            state: EphemeralState::default(),
+            seen: SeenTuples::default(),
+            subscribers,
+            connections: Mutex::new(HashMap::new()),
+            next_connection_id: AtomicUsize::new(0),
+        }
+    }
+
+    fn register_connection(&self, sender: mpsc::UnboundedSender<RepoMessage>) -> ConnectionId {
+        let id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        self.connections.lock().insert(id, sender);
+        id
+    }
+
+    fn unregister_connection(&self, id: ConnectionId) {
+        self.connections.lock().remove(&id);
+    }
+
+    /// Send `msg` to every registered connection except `exclude` (the one
+    /// it arrived on, if any).
+    fn broadcast(&self, msg: RepoMessage, exclude: Option<ConnectionId>) {
+        for (id, sender) in self.connections.lock().iter() {
+            if Some(*id) == exclude {
+                continue;
+            }
+            // The connection's outgoing task may already have shut down;
+            // a dead send here just means that peer is on its way out.
+            let _ = sender.send(msg.clone());
+        }
+    }
+}
+
+fn hub_for(repo_id: &RepoId) -> Arc<EphemeralHub> {
+    static HUBS: OnceLock<Mutex<HashMap<RepoId, Arc<EphemeralHub>>>> = OnceLock::new();
+    HUBS.get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .entry(repo_id.clone())
+        .or_insert_with(|| Arc::new(EphemeralHub::new()))
+        .clone()
+}
+
+/// One connection's registration with its repo's [`EphemeralHub`]. Dropping
+/// this deregisters the connection, so other connections stop trying to
+/// forward ephemeral traffic to it.
+pub(crate) struct EphemeralConnection {
+    repo_id: RepoId,
+    id: ConnectionId,
+}
+
+impl EphemeralConnection {
+    /// Registers a new connection on `repo_id`'s hub. Returns the guard
+    /// (keep it alive for the connection's lifetime) and the receiving half
+    /// of the channel other connections' re-broadcasts (and this repo's own
+    /// `broadcast_ephemeral` calls) arrive on — drain it into the
+    /// connection's outgoing sink.
+    pub(crate) fn register(
+        repo_id: RepoId,
+    ) -> (Self, mpsc::UnboundedReceiver<RepoMessage>) {
+        let hub = hub_for(&repo_id);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let id = hub.register_connection(tx);
+        (EphemeralConnection { repo_id, id }, rx)
+    }
+
+    /// Handle one incoming `RepoMessage` from this connection. If it's a
+    /// novel `RepoMessage::Ephemeral`, publish it to local subscribers and,
+    /// as long as it still has hops left, flood it (with `hops_remaining`
+    /// decremented) to every other connection on this repo; either way
+    /// return `true` so the caller still passes it further up the stream. A
+    /// duplicate (a loop) returns `false` so the caller drops it instead.
+    /// Any other kind of message is passed through untouched.
+    pub(crate) fn handle_incoming(&self, msg: &RepoMessage) -> bool {
+        let RepoMessage::Ephemeral {
+            from_repo_id,
+            document_id,
+            session,
+            count,
+            hops_remaining,
+            data,
+        } = msg
+        else {
+            return true;
+        };
+        let hub = hub_for(&self.repo_id);
+        if !hub.seen.observe(from_repo_id, *session, *count) {
+            return false;
+        }
+        let _ = hub.subscribers.send(EphemeralEvent {
+            from_repo_id: from_repo_id.clone(),
+            document_id: document_id.clone(),
+            data: data.clone(),
+        });
+        // A message that's used up all its hops is delivered here (above)
+        // but not forwarded any further, independent of whether the LRU
+        // cache above still recognizes it as novel — that cache bounds
+        // *history*, not how many times a message can circulate.
+        if let Some(remaining) = hops_remaining.checked_sub(1) {
+            let mut forwarded = msg.clone();
+            if let RepoMessage::Ephemeral { hops_remaining, .. } = &mut forwarded {
+                *hops_remaining = remaining;
+            }
+            hub.broadcast(forwarded, Some(self.id));
+        }
+        true
+    }
+}
+
+impl Drop for EphemeralConnection {
+    fn drop(&mut self) {
+        hub_for(&self.repo_id).unregister_connection(self.id);
+    }
+}
+
+impl RepoHandle {
+    /// Flood an ephemeral payload (presence, cursor position, typing
+    /// indicator, ...) to every connected peer. The payload is never
+    /// written to storage and carries no delivery guarantee.
+    pub fn broadcast_ephemeral(&self, document_id: DocumentId, data: Vec<u8>) {
+        let hub = hub_for(self.get_repo_id());
+        let (session, count) = hub.state.next_stamp();
+        let msg = RepoMessage::Ephemeral {
+            from_repo_id: self.get_repo_id().clone(),
+            document_id,
+            session,
+            count,
+            hops_remaining: MAX_EPHEMERAL_HOPS,
+            data,
+        };
+        tracing::trace!(?msg, "Broadcasting ephemeral message");
+        hub.broadcast(msg, None);
+    }
+
+    /// A stream of ephemeral payloads received from any connected peer,
+    /// after loop-prevention has already discarded duplicates.
+    pub fn subscribe_ephemeral(&self) -> impl Stream<Item = EphemeralEvent> {
+        let hub = hub_for(self.get_repo_id());
+        BroadcastStream::new(hub.subscribers.subscribe()).filter_map(|r| async move {
+            match r {
+                Ok(event) => Some(event),
+                Err(_lagged) => None,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_observation_of_a_tuple_is_accepted() {
+        let seen = SeenTuples::default();
+        assert!(seen.observe(&RepoId("peer-a".to_string()), 1, 0));
+    }
+
+    #[test]
+    fn repeated_tuple_is_rejected_as_a_duplicate() {
+        let seen = SeenTuples::default();
+        let sender = RepoId("peer-a".to_string());
+        assert!(seen.observe(&sender, 1, 0));
+        assert!(!seen.observe(&sender, 1, 0));
+    }
+
+    #[test]
+    fn same_sender_and_count_with_a_different_session_is_not_a_duplicate() {
+        // This is the regression case for the restart/reconnect bug: a new
+        // process incarnation reuses counts starting from 0, which must not
+        // collide with a previous incarnation's cached counts.
+        let seen = SeenTuples::default();
+        let sender = RepoId("peer-a".to_string());
+        assert!(seen.observe(&sender, 1, 0));
+        assert!(seen.observe(&sender, 2, 0));
+    }
+
+    #[test]
+    fn different_senders_with_the_same_session_and_count_are_independent() {
+        let seen = SeenTuples::default();
+        assert!(seen.observe(&RepoId("peer-a".to_string()), 1, 0));
+        assert!(seen.observe(&RepoId("peer-b".to_string()), 1, 0));
+    }
+
+    #[test]
+    fn ephemeral_state_assigns_increasing_counts_under_one_session() {
+        let state = EphemeralState::default();
+        let (session_a, count_a) = state.next_stamp();
+        let (session_b, count_b) = state.next_stamp();
+        assert_eq!(session_a, session_b);
+        assert_eq!(count_b, count_a + 1);
+    }
+
+    fn ephemeral_msg(hops_remaining: u8) -> RepoMessage {
+        RepoMessage::Ephemeral {
+            from_repo_id: RepoId("hop-test-sender".to_string()),
+            document_id: DocumentId("hop-test-doc".to_string()),
+            session: 1,
+            count: 0,
+            hops_remaining,
+            data: vec![],
+        }
+    }
+
+    #[test]
+    fn novel_message_with_hops_remaining_is_forwarded_with_decremented_hop_count() {
+        let repo_id = RepoId("hop-test-forward".to_string());
+        let (conn_a, _rx_a) = EphemeralConnection::register(repo_id.clone());
+        let (_conn_b, mut rx_b) = EphemeralConnection::register(repo_id);
+
+        assert!(conn_a.handle_incoming(&ephemeral_msg(3)));
+
+        match rx_b.try_recv().expect("message should have been forwarded") {
+            RepoMessage::Ephemeral { hops_remaining, .. } => assert_eq!(hops_remaining, 2),
+            other => panic!("expected an Ephemeral message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn message_with_no_hops_remaining_is_delivered_but_not_forwarded() {
+        // This is the regression case for the hop-bound bug: a message
+        // that's used up its hop budget must still reach local subscribers
+        // (handle_incoming returns true) but must not be re-broadcast,
+        // independent of whether the LRU dedup cache would otherwise treat
+        // it as novel and happily flood it again.
+        let repo_id = RepoId("hop-test-exhausted".to_string());
+        let (conn_a, _rx_a) = EphemeralConnection::register(repo_id.clone());
+        let (_conn_b, mut rx_b) = EphemeralConnection::register(repo_id);
+
+        assert!(conn_a.handle_incoming(&ephemeral_msg(0)));
+
+        assert!(rx_b.try_recv().is_err());
+    }
+
+    #[test]
+    fn duplicate_message_is_still_rejected_regardless_of_hops_remaining() {
+        let repo_id = RepoId("hop-test-duplicate".to_string());
+        let (conn, _rx) = EphemeralConnection::register(repo_id);
+        assert!(conn.handle_incoming(&ephemeral_msg(5)));
+        assert!(!conn.handle_incoming(&ephemeral_msg(5)));
+    }
+}
@@ -1,69 +1,362 @@
+use crate::basalt::BasaltConnection;
+use crate::ephemeral::EphemeralConnection;
 use crate::interfaces::{Message, NetworkError, ProtocolVersion, RepoId, RepoMessage};
+use crate::peering::{self, PeeringManager};
 use crate::repo::RepoHandle;
+use crate::secure_transport::SecureTransport;
 use futures::{Sink, SinkExt, Stream, StreamExt};
+use parking_lot::Mutex as SyncMutex;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_stream::wrappers::{IntervalStream, UnboundedReceiverStream};
+
+/// Adapts an `mpsc::UnboundedSender` into a `Sink`, so the repo actor can
+/// write `RepoMessage`s through the same interface it always has while a
+/// background task (see [`RepoHandle::connect_stream_with_transport`]) merges
+/// that traffic with messages re-broadcast from other connections before
+/// they hit the wire.
+struct UnboundedSink<T>(mpsc::UnboundedSender<T>);
+
+impl<T> Sink<T> for UnboundedSink<T> {
+    type Error = NetworkError;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        self.0
+            .send(item)
+            .map_err(|_| NetworkError::Error("outgoing message channel closed".to_string()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
 
 /// Which direction a connection passed to [`crate::RepoHandle::new_remote_repo`] is going
+#[derive(Debug)]
 pub enum ConnDirection {
     Incoming,
     Outgoing,
+    /// Neither side dialed the other (e.g. a hole-punched UDP/TCP socket):
+    /// both ends race an initiator-nonce exchange to agree on who plays
+    /// `Incoming` and who plays `Outgoing` before falling back to the
+    /// existing Join/Peer flow.
+    SimultaneousOpen,
 }
 
 impl RepoHandle {
+    /// Like [`Self::connect_stream_with_transport`] with no transport or
+    /// peering. See that function for the meaning of the returned
+    /// [`JoinHandle`](tokio::task::JoinHandle).
     pub async fn connect_stream<Str, Snk, SendErr, RecvErr>(
         &self,
-        mut stream: Str,
-        mut sink: Snk,
+        stream: Str,
+        sink: Snk,
         direction: ConnDirection,
-    ) -> Result<(), NetworkError>
+    ) -> Result<tokio::task::JoinHandle<()>, NetworkError>
     where
         SendErr: std::error::Error + Send + Sync + 'static,
         RecvErr: std::error::Error + Send + Sync + 'static,
         Snk: Sink<Message, Error = SendErr> + Send + 'static + Unpin,
         Str: Stream<Item = Result<Message, RecvErr>> + Send + 'static + Unpin,
     {
+        self.connect_stream_with_transport(stream, sink, direction, None, None)
+            .await
+    }
+
+    /// Like [`Self::connect_stream`], but first runs `transport`'s mutual-auth
+    /// key-agreement over the raw stream/sink and checks that the resulting
+    /// authenticated public key matches the key already known for the
+    /// `RepoId` the peer claims in its `Join`/`Peer` message (see
+    /// [`SecureTransport::known_repo_key`]); a `RepoId` with no known key is
+    /// not checked, and is pinned to the key it just authenticated with
+    /// (see [`SecureTransport::observe_repo_key`]). Pass `None` to keep the
+    /// existing unauthenticated, cleartext path.
+    ///
+    /// Once connected, the connection is kept alive with a protocol-level
+    /// `Ping`/`Pong` loop (idle connections are closed if a `Pong` doesn't
+    /// arrive within [`crate::peering::KEEPALIVE_DEADLINE`]) and, if
+    /// `peering` is given, gossips this node's currently known peer
+    /// addresses once right after the handshake and feeds any addresses the
+    /// other side gossips back into it — see [`crate::peering::PeeringManager`].
+    ///
+    /// Returns a [`JoinHandle`](tokio::task::JoinHandle) for the task that
+    /// drives this connection's outgoing traffic; it resolves once the
+    /// connection actually ends (the wire sink errors, or a keepalive
+    /// timeout aborts it), so a [`crate::peering::Dialer`] built on top of
+    /// this should `.await` it before redialing, rather than returning as
+    /// soon as the handshake completes.
+    pub async fn connect_stream_with_transport<Str, Snk, SendErr, RecvErr>(
+        &self,
+        stream: Str,
+        sink: Snk,
+        direction: ConnDirection,
+        transport: Option<Arc<dyn SecureTransport>>,
+        peering: Option<Arc<PeeringManager>>,
+    ) -> Result<tokio::task::JoinHandle<()>, NetworkError>
+    where
+        SendErr: std::error::Error + Send + Sync + 'static,
+        RecvErr: std::error::Error + Send + Sync + 'static,
+        Snk: Sink<Message, Error = SendErr> + Send + 'static + Unpin,
+        Str: Stream<Item = Result<Message, RecvErr>> + Send + 'static + Unpin,
+    {
+        // Normalize both error types to `NetworkError` up front so the raw
+        // stream/sink already satisfy `SecureTransport::run_handshake`'s
+        // object-safe `BoxedMessageStream`/`BoxedMessageSink` signature
+        // (a `dyn Stream`/`dyn Sink` needs one concrete `Error`/`Item` type).
+        let mut stream =
+            stream.map(|r| r.map_err(|e| NetworkError::Error(format!("error receiving: {}", e))));
+        let mut sink =
+            sink.sink_map_err(|e| NetworkError::Error(format!("error sending: {}", e)));
+
+        let secure_session = if let Some(transport) = &transport {
+            Some(transport.run_handshake(&mut stream, &mut sink).await?)
+        } else {
+            None
+        };
+
+        // From here on, if a secure transport was used, every `Message` —
+        // including the Join/Peer exchange below — travels boxed in the
+        // session's keys rather than in the clear. Box both sides so the
+        // wrapped and unwrapped branches (different concrete types) unify.
+        let (mut stream, mut sink): (
+            Box<dyn Stream<Item = Result<Message, NetworkError>> + Send + Unpin>,
+            Box<dyn Sink<Message, Error = NetworkError> + Send + Unpin>,
+        ) = match &secure_session {
+            Some(session) => {
+                let (stream, sink) = session.wrap(stream, sink);
+                (Box::new(stream), Box::new(sink))
+            }
+            None => (Box::new(stream), Box::new(sink)),
+        };
+
         let other_id = self.handshake(&mut stream, &mut sink, direction).await?;
         tracing::trace!(?other_id, repo_id=?self.get_repo_id(), "Handshake complete");
 
-        let stream = stream.map({
-            let repo_id = self.get_repo_id().clone();
-            move |msg| match msg {
-                Ok(Message::Repo(repo_msg)) => {
-                    tracing::trace!(?repo_msg, repo_id=?repo_id, "Received repo message");
-                    Ok(repo_msg)
-                }
-                Ok(m) => {
-                    tracing::warn!(?m, repo_id=?repo_id, "Received non-repo message");
-                    Err(NetworkError::Error(
-                        "unexpected non-repo message".to_string(),
-                    ))
+        if let (Some(transport), Some(session)) = (&transport, &secure_session) {
+            match transport.known_repo_key(&other_id) {
+                // Already pinned from a prior handshake: reject the peer
+                // outright if its authenticated key doesn't match, rather
+                // than silently trusting whoever shows up claiming this
+                // `RepoId`.
+                Some(expected) => session.bind_repo_id(&other_id, &expected)?,
+                // First time we've seen this `RepoId`: pin the key it just
+                // authenticated with (trust-on-first-use), so a future
+                // impersonator claiming the same `RepoId` with a different
+                // key is rejected by the branch above instead.
+                None => transport.observe_repo_key(&other_id, session.remote_public),
+            }
+        }
+
+        // Gossip this node's currently known peer addresses once, right
+        // after the handshake, so a new node can discover the rest of the
+        // mesh from a single bootstrap peer. Addresses the other side sends
+        // back are fed into `peering` as they're intercepted below,
+        // alongside `Ping`/`Pong`.
+        if let Some(peering) = &peering {
+            let addrs: Vec<SocketAddr> = peering
+                .peer_states()
+                .into_iter()
+                .map(|(addr, _)| addr)
+                .collect();
+            sink.send(Message::PeerGossip { addrs })
+                .await
+                .map_err(|e| NetworkError::Error(format!("error sending peer gossip: {}", e)))?;
+        }
+
+        // `ephemeral_conn` is this connection's registration with the repo's
+        // ephemeral hub: the incoming side uses it to dedup and re-broadcast
+        // novel `RepoMessage::Ephemeral`s to every other connection, and
+        // `ephemeral_rx` is where those re-broadcasts (plus our own
+        // `broadcast_ephemeral` calls) arrive to be forwarded onto the wire.
+        let (ephemeral_conn, ephemeral_rx) =
+            EphemeralConnection::register(self.get_repo_id().clone());
+        let ephemeral_conn = Arc::new(ephemeral_conn);
+
+        // `basalt_conn` is this connection's registration for directed
+        // Basalt peer-sampling gossip with `other_id` (a no-op if this repo
+        // has no view registered via `crate::basalt::register_basalt`);
+        // `basalt_tx`/`basalt_rx` carry both `run_gossip_round`-initiated
+        // requests addressed to `other_id` and our own replies to its
+        // requests out onto the wire.
+        let (basalt_conn, basalt_tx, basalt_rx) =
+            BasaltConnection::register(self.get_repo_id().clone(), other_id.clone());
+        let basalt_conn = Arc::new(basalt_conn);
+
+        // The repo actor keeps writing `RepoMessage`s through this channel
+        // exactly as it always has; the outgoing task spawned below merges
+        // that traffic with ephemeral re-broadcasts, Basalt gossip, and
+        // keepalive `Ping`/`Pong` messages before any of it reaches the wire
+        // sink.
+        let (repo_tx, repo_rx) = mpsc::unbounded_channel::<RepoMessage>();
+        let (keepalive_tx, keepalive_rx) = mpsc::unbounded_channel::<Message>();
+
+        let repo_messages = futures::stream::select(
+            UnboundedReceiverStream::new(repo_rx),
+            UnboundedReceiverStream::new(ephemeral_rx),
+        )
+        .filter_map(|msg| {
+            futures::future::ready(match msg {
+                RepoMessage::Sync { .. } | RepoMessage::Ephemeral { .. } => {
+                    Some(Message::Repo(msg))
                 }
-                Err(e) => {
-                    tracing::error!(?e, repo_id=?repo_id, "Error receiving repo message");
-                    Err(NetworkError::Error(format!(
-                        "error receiving repo message: {}",
-                        e
-                    )))
+                _ => None,
+            })
+        });
+        let outgoing = futures::stream::select(
+            futures::stream::select(repo_messages, UnboundedReceiverStream::new(keepalive_rx)),
+            UnboundedReceiverStream::new(basalt_rx),
+        )
+        .map(Ok);
+
+        // Spawned up front (rather than after building the inbound stream
+        // below) so its `AbortHandle` is available to the keepalive branch:
+        // on a keepalive timeout we need to tear down the write half too,
+        // not just stop reading. The returned `JoinHandle` is this
+        // function's way of telling a caller (e.g. a `Dialer`) when the
+        // connection has actually ended, dropping `ephemeral_conn`'s and
+        // `basalt_conn`'s hub registrations (via their `Drop` impls) along
+        // with it.
+        let connection_task = tokio::spawn({
+            let ephemeral_conn = ephemeral_conn.clone();
+            let basalt_conn = basalt_conn.clone();
+            let mut sink = sink;
+            let mut outgoing = outgoing;
+            async move {
+                let _ephemeral_conn = ephemeral_conn;
+                let _basalt_conn = basalt_conn;
+                if let Err(e) = sink.send_all(&mut outgoing).await {
+                    tracing::warn!(?e, "outgoing connection sink closed");
                 }
             }
         });
+        let outgoing_abort = connection_task.abort_handle();
 
-        let sink_repo_id = self.get_repo_id().clone();
-        let sink = sink
-            .with_flat_map::<RepoMessage, _, _>(move |msg| {
-                tracing::trace!(?msg, repo_id=?sink_repo_id, "Sending repo message");
-                match msg {
-                    RepoMessage::Sync { .. } => futures::stream::iter(vec![Ok(Message::Repo(msg))]),
-                    _ => futures::stream::iter(vec![]),
+        // Protocol-level keepalive: reply to the peer's `Ping`s with `Pong`,
+        // send our own `Ping` on an idle timer, and track the last `Pong`
+        // we've seen so an unresponsive connection is closed (both the
+        // inbound stream and the outgoing task above) rather than left to
+        // hang forever. `PeerGossip` and `BasaltGossip` replies are
+        // intercepted here too rather than passed up as repo traffic.
+        let last_pong = Arc::new(SyncMutex::new(Instant::now()));
+
+        enum RawEvent {
+            Message(Result<Message, NetworkError>),
+            KeepaliveTick,
+        }
+
+        let ticks = IntervalStream::new(tokio::time::interval(peering::KEEPALIVE_INTERVAL))
+            .map(|_| RawEvent::KeepaliveTick);
+        let stream = futures::stream::select(stream.map(RawEvent::Message), ticks)
+            .map({
+                let last_pong = last_pong.clone();
+                let keepalive_tx = keepalive_tx.clone();
+                let peering = peering.clone();
+                let basalt_conn = basalt_conn.clone();
+                let basalt_tx = basalt_tx.clone();
+                // `Option<Option<Result<Message, NetworkError>>>`: outer
+                // `None` ends the stream (keepalive deadline exceeded),
+                // `Some(None)` drops an intercepted item, `Some(Some(msg))`
+                // passes `msg` through unchanged.
+                move |event| match event {
+                    RawEvent::KeepaliveTick => {
+                        if peering::keepalive_expired(*last_pong.lock()) {
+                            tracing::warn!(
+                                "keepalive deadline exceeded, closing connection"
+                            );
+                            outgoing_abort.abort();
+                            return None;
+                        }
+                        let _ = keepalive_tx.send(Message::Ping);
+                        Some(None)
+                    }
+                    RawEvent::Message(Ok(msg)) if peering::is_keepalive(&msg) => {
+                        if matches!(msg, Message::Pong) {
+                            *last_pong.lock() = Instant::now();
+                        } else {
+                            let _ = keepalive_tx.send(Message::Pong);
+                        }
+                        Some(None)
+                    }
+                    RawEvent::Message(Ok(Message::PeerGossip { addrs })) => {
+                        if let Some(peering) = &peering {
+                            for addr in addrs {
+                                peering.add_peer(addr);
+                            }
+                        }
+                        Some(None)
+                    }
+                    RawEvent::Message(Ok(msg @ Message::BasaltGossip { .. })) => {
+                        if let Some(response) = basalt_conn.handle_incoming(&msg) {
+                            let _ = basalt_tx.send(response);
+                        }
+                        Some(None)
+                    }
+                    RawEvent::Message(other) => Some(Some(other)),
+                }
+            })
+            .take_while(|event| futures::future::ready(event.is_some()))
+            .map(|event| event.unwrap())
+            .filter_map(futures::future::ready);
+
+        let stream = stream
+            .map({
+                let repo_id = self.get_repo_id().clone();
+                move |msg| match msg {
+                    Ok(Message::Repo(repo_msg)) => {
+                        tracing::trace!(?repo_msg, repo_id=?repo_id, "Received repo message");
+                        Ok(repo_msg)
+                    }
+                    Ok(m) => {
+                        tracing::warn!(?m, repo_id=?repo_id, "Received non-repo message");
+                        Err(NetworkError::Error(
+                            "unexpected non-repo message".to_string(),
+                        ))
+                    }
+                    Err(e) => {
+                        tracing::error!(?e, repo_id=?repo_id, "Error receiving repo message");
+                        Err(NetworkError::Error(format!(
+                            "error receiving repo message: {}",
+                            e
+                        )))
+                    }
                 }
             })
-            .sink_map_err(|e| {
-                tracing::error!(?e, "Error sending repo message");
-                NetworkError::Error(format!("error sending repo message: {}", e))
+            .filter_map({
+                let ephemeral_conn = ephemeral_conn.clone();
+                move |msg| {
+                    let ephemeral_conn = ephemeral_conn.clone();
+                    async move {
+                        match &msg {
+                            // Novel ephemeral messages are re-broadcast to this
+                            // repo's other connections as a side effect of
+                            // `handle_incoming`; duplicates/loops are dropped
+                            // rather than passed up or re-broadcast again.
+                            Ok(repo_msg) if !ephemeral_conn.handle_incoming(repo_msg) => None,
+                            _ => Some(msg),
+                        }
+                    }
+                }
             });
 
-        self.new_remote_repo(other_id, Box::new(stream), Box::new(sink));
+        self.new_remote_repo(
+            other_id,
+            Box::new(stream),
+            Box::new(UnboundedSink(repo_tx)),
+        );
 
-        Ok(())
+        Ok(connection_task)
     }
 
     async fn handshake<Str, Snk, SendErr, RecvErr>(
@@ -78,7 +371,12 @@ impl RepoHandle {
         Str: Stream<Item = Result<Message, RecvErr>> + Unpin,
         Snk: Sink<Message, Error = SendErr> + Unpin,
     {
+        let direction = match direction {
+            ConnDirection::SimultaneousOpen => self.resolve_sim_open_roles(stream, sink).await?,
+            other => other,
+        };
         match direction {
+            ConnDirection::SimultaneousOpen => unreachable!("resolved above"),
             ConnDirection::Incoming => {
                 if let Some(msg) = stream.next().await {
                     let other_id = match msg {
@@ -132,4 +430,109 @@ impl RepoHandle {
             }
         }
     }
+
+    /// Runs the multistream-select-style simultaneous-open extension: both
+    /// sides send a random initiator nonce, and the side with the larger
+    /// nonce becomes `Incoming` (the "select"/responder) while the other
+    /// becomes `Outgoing` (the initiator). A tie is vanishingly unlikely but
+    /// is re-rolled rather than left ambiguous.
+    async fn resolve_sim_open_roles<Str, Snk, SendErr, RecvErr>(
+        &self,
+        stream: &mut Str,
+        sink: &mut Snk,
+    ) -> Result<ConnDirection, NetworkError>
+    where
+        SendErr: std::error::Error + Send + Sync + 'static,
+        RecvErr: std::error::Error + Send + Sync + 'static,
+        Str: Stream<Item = Result<Message, RecvErr>> + Unpin,
+        Snk: Sink<Message, Error = SendErr> + Unpin,
+    {
+        loop {
+            let our_nonce: u64 = rand::random();
+            sink.send(Message::SimOpenHello {
+                sender: self.get_repo_id().clone(),
+                nonce: our_nonce,
+            })
+            .await
+            .map_err(|e| NetworkError::Error(format!("error sending sim-open nonce: {}", e)))?;
+
+            let their_nonce = match stream.next().await {
+                Some(Ok(Message::SimOpenHello { nonce, .. })) => nonce,
+                Some(Ok(other)) => {
+                    return Err(NetworkError::Error(format!(
+                        "unexpected message (expecting sim-open hello): {:?}",
+                        other
+                    )))
+                }
+                Some(Err(e)) => {
+                    return Err(NetworkError::Error(format!(
+                        "error receiving sim-open nonce: {}",
+                        e
+                    )))
+                }
+                None => {
+                    return Err(NetworkError::Error(
+                        "unexpected end of receive stream during sim-open".to_string(),
+                    ))
+                }
+            };
+
+            match resolve_role_from_nonces(our_nonce, their_nonce) {
+                Some(direction) => return Ok(direction),
+                None => {
+                    tracing::trace!("sim-open nonce tie, re-rolling");
+                    continue;
+                }
+            }
+        }
+    }
+}
+
+/// The pure tie-break rule at the heart of [`RepoHandle::resolve_sim_open_roles`]:
+/// the larger nonce plays `Incoming`, the smaller plays `Outgoing`, and a tie
+/// (`None`) means both sides must re-roll and try again rather than risk
+/// disagreeing on who's who.
+fn resolve_role_from_nonces(our_nonce: u64, their_nonce: u64) -> Option<ConnDirection> {
+    match our_nonce.cmp(&their_nonce) {
+        std::cmp::Ordering::Greater => Some(ConnDirection::Incoming),
+        std::cmp::Ordering::Less => Some(ConnDirection::Outgoing),
+        std::cmp::Ordering::Equal => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn larger_nonce_is_incoming_smaller_is_outgoing() {
+        assert!(matches!(
+            resolve_role_from_nonces(5, 3),
+            Some(ConnDirection::Incoming)
+        ));
+        assert!(matches!(
+            resolve_role_from_nonces(3, 5),
+            Some(ConnDirection::Outgoing)
+        ));
+    }
+
+    #[test]
+    fn tied_nonces_reroll_instead_of_picking_a_side() {
+        assert!(resolve_role_from_nonces(7, 7).is_none());
+    }
+
+    #[test]
+    fn the_two_sides_of_a_sim_open_never_agree_on_direction() {
+        // Whichever side sees the larger nonce as "ours" must resolve to the
+        // opposite role from the side that sees it as "theirs", so the two
+        // ends of one sim-open never both end up `Incoming` or `Outgoing`.
+        let (our_nonce, their_nonce) = (9_u64, 4_u64);
+        let our_direction = resolve_role_from_nonces(our_nonce, their_nonce);
+        let their_direction = resolve_role_from_nonces(their_nonce, our_nonce);
+        match (our_direction, their_direction) {
+            (Some(ConnDirection::Incoming), Some(ConnDirection::Outgoing)) => {}
+            (Some(ConnDirection::Outgoing), Some(ConnDirection::Incoming)) => {}
+            other => panic!("expected opposite roles, got {other:?}"),
+        }
+    }
 }
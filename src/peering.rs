@@ -0,0 +1,165 @@
+//! A full-mesh peering subsystem, modeled on netapp's `fullmesh` peering:
+//! given a set of bootstrap addresses and a dialer, keep redialing every
+//! desired peer until it's connected, detect dead connections with a
+//! protocol-level keepalive, and gossip known peer addresses so new nodes
+//! can discover the rest of the mesh from a single bootstrap.
+//!
+//! `connect_stream` on its own only ever makes one connection attempt: its
+//! keepalive closes a dead link, but nothing redials it; `PeeringManager`
+//! is the layer that keeps a mesh of those connections up over time.
+use crate::interfaces::{Message, NetworkError};
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How often an idle connection emits a `Message::Ping` to prove it's still
+/// alive.
+pub(crate) const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long to wait for a `Message::Pong` before giving up on a connection
+/// and re-queuing it for reconnection.
+pub(crate) const KEEPALIVE_DEADLINE: Duration = Duration::from_secs(30);
+
+/// The smallest backoff applied to a redial attempt after a connection
+/// fails or ends.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+
+/// The largest backoff a peer's reconnect loop will back off to, no matter
+/// how many consecutive attempts have failed.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A function that dials a peer's address, runs the sync connection over
+/// it, and resolves once that connection ends (cleanly or with an error) so
+/// the manager knows it's time to redial.
+pub type Dialer =
+    Arc<dyn Fn(SocketAddr) -> BoxFuture<'static, Result<(), NetworkError>> + Send + Sync>;
+
+/// The current connectivity state of one desired peer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerState {
+    Connecting,
+    Connected,
+    Backoff { until: Instant, attempt: u32 },
+}
+
+struct PeerEntry {
+    addr: SocketAddr,
+    state: PeerState,
+    redial_task: tokio::task::JoinHandle<()>,
+}
+
+/// Keeps a table of desired peers and redials them with exponential backoff
+/// whenever their connection ends, maintaining full-mesh connectivity
+/// across the set of bootstrap peers plus any peers gossiped by the mesh
+/// itself.
+pub struct PeeringManager {
+    dialer: Dialer,
+    peers: Mutex<HashMap<SocketAddr, PeerEntry>>,
+}
+
+impl PeeringManager {
+    /// Start a `PeeringManager` that immediately begins dialing `bootstrap`
+    /// and keeps redialing every peer it's told about (via `add_peer`) until
+    /// that peer is removed with `remove_peer`. Each redial loop holds its
+    /// own reference to the manager, so dropping the returned handle does
+    /// not stop them.
+    pub fn new(bootstrap: Vec<SocketAddr>, dialer: Dialer) -> Arc<Self> {
+        let manager = Arc::new(PeeringManager {
+            dialer,
+            peers: Mutex::new(HashMap::new()),
+        });
+        for addr in bootstrap {
+            manager.add_peer(addr);
+        }
+        manager
+    }
+
+    /// Add a peer address (e.g. one gossiped by an already-connected peer)
+    /// to the desired set, starting a redial loop for it if it isn't
+    /// already known.
+    pub fn add_peer(self: &Arc<Self>, addr: SocketAddr) {
+        let mut peers = self.peers.lock();
+        if peers.contains_key(&addr) {
+            return;
+        }
+        let this = self.clone();
+        let redial_task = tokio::spawn(async move { this.redial_loop(addr).await });
+        peers.insert(
+            addr,
+            PeerEntry {
+                addr,
+                state: PeerState::Connecting,
+                redial_task,
+            },
+        );
+    }
+
+    /// Stop redialing a peer and drop it from the desired set, e.g. because
+    /// a peer-sampling round (see [`crate::basalt::Basalt`]) no longer
+    /// includes it in the view.
+    pub fn remove_peer(&self, addr: SocketAddr) {
+        if let Some(entry) = self.peers.lock().remove(&addr) {
+            entry.redial_task.abort();
+        }
+    }
+
+    /// The connectivity state of every peer this manager currently knows
+    /// about.
+    pub fn peer_states(&self) -> Vec<(SocketAddr, PeerState)> {
+        self.peers
+            .lock()
+            .values()
+            .map(|entry| (entry.addr, entry.state.clone()))
+            .collect()
+    }
+
+    fn set_state(&self, addr: SocketAddr, state: PeerState) {
+        if let Some(entry) = self.peers.lock().get_mut(&addr) {
+            entry.state = state;
+        }
+    }
+
+    async fn redial_loop(self: Arc<Self>, addr: SocketAddr) {
+        let mut attempt: u32 = 0;
+        loop {
+            self.set_state(addr, PeerState::Connecting);
+            match (self.dialer)(addr).await {
+                Ok(()) => {
+                    tracing::trace!(?addr, "Connection ended cleanly, redialing");
+                    attempt = 0;
+                }
+                Err(e) => {
+                    tracing::warn!(?addr, error=?e, attempt, "Connection attempt failed");
+                }
+            }
+            let backoff = std::cmp::min(INITIAL_BACKOFF.saturating_mul(1 << attempt.min(16)), MAX_BACKOFF);
+            let until = Instant::now() + backoff;
+            self.set_state(addr, PeerState::Backoff { until, attempt });
+            tokio::time::sleep(backoff).await;
+            attempt = attempt.saturating_add(1);
+        }
+    }
+
+    /// Mark a peer as connected once its `connect_stream` handshake
+    /// completes; called by the dialer closure right before it starts
+    /// forwarding traffic.
+    pub fn mark_connected(&self, addr: SocketAddr) {
+        self.set_state(addr, PeerState::Connected);
+    }
+}
+
+/// Is `msg` a keepalive message that a connection's idle-timer loop should
+/// intercept rather than hand up to the repo's sync logic?
+pub(crate) fn is_keepalive(msg: &Message) -> bool {
+    matches!(msg, Message::Ping | Message::Pong)
+}
+
+/// Has a connection gone too long without a `Pong` in reply to our last
+/// `Ping`, meaning it should be treated as dead and closed?
+pub(crate) fn keepalive_expired(last_pong: Instant) -> bool {
+    last_pong.elapsed() > KEEPALIVE_DEADLINE
+}
@@ -0,0 +1,450 @@
+//! Optional authenticated+encrypted handshake layer that can run before the
+//! plaintext `Message::Join`/`Message::Peer` exchange in [`crate::network_connect`].
+//!
+//! Without this module `handshake` trusts whatever [`RepoId`] the peer claims
+//! in its `Join`/`Peer` message and ships every later `Message` in the clear.
+//! A [`SecureTransport`] implementation proves possession of a static ed25519
+//! keypair and derives a pair of symmetric session keys; [`SecureStream`] and
+//! [`SecureSink`] then use those keys to box every later `Message` in a
+//! `Message::Secure` envelope. The negotiated public key is bound to the
+//! `RepoId` returned from the handshake, so a peer can no longer claim an
+//! identity it doesn't hold the key for.
+use crate::interfaces::{Message, NetworkError, RepoId};
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use parking_lot::Mutex;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use x25519_dalek::{EphemeralSecret, PublicKey as XPublicKey};
+
+/// A `Message` stream whose concrete type and receive-error type have been
+/// erased, so [`SecureTransport`] can be used as `Arc<dyn SecureTransport>`.
+pub type BoxedMessageStream = dyn Stream<Item = Result<Message, NetworkError>> + Unpin + Send;
+
+/// A `Message` sink with its concrete type and send-error type erased, for
+/// the same reason as [`BoxedMessageStream`].
+pub type BoxedMessageSink = dyn Sink<Message, Error = NetworkError> + Unpin + Send;
+
+/// A repo's long-lived identity used to authenticate the secure handshake.
+///
+/// This is independent of [`RepoId`]: the `RepoId` is a logical name for a
+/// repo, while the `Keypair` is the cryptographic identity the handshake
+/// checks the remote peer against.
+pub struct Keypair {
+    signing_key: SigningKey,
+}
+
+impl Keypair {
+    pub fn generate() -> Self {
+        Keypair {
+            signing_key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    pub fn public(&self) -> VerifyingKey {
+        self.signing_key.verifying_key()
+    }
+}
+
+/// The four messages exchanged during the mutual-auth key-agreement, modeled
+/// on the secret-handshake protocol used by netapp: each side sends an
+/// ephemeral Diffie-Hellman public key, then a signature over both ephemeral
+/// keys proving possession of the long-term signing key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum HandshakeMessage {
+    EphemeralKey {
+        ephemeral_public: [u8; 32],
+    },
+    Auth {
+        static_public: [u8; 32],
+        signature: [u8; 64],
+    },
+}
+
+/// The outcome of a successful secure handshake: the authenticated static
+/// public key of the remote peer and the symmetric keys used to box traffic
+/// in each direction. Use [`SecureSession::wrap`] to actually apply those
+/// keys to a connection's stream/sink.
+pub struct SecureSession {
+    pub remote_public: VerifyingKey,
+    send_key: [u8; 32],
+    recv_key: [u8; 32],
+}
+
+/// A pluggable authenticated, encrypted handshake stage. Implementations run
+/// a mutual key-agreement over the raw `Message` stream/sink *before*
+/// `Message::Join`/`Message::Peer` and hand back a [`SecureSession`] whose
+/// keys [`SecureSession::wrap`] then uses to box every later message.
+///
+/// Repos that don't opt in to a `SecureTransport` keep using the existing
+/// unauthenticated, cleartext path unchanged. The stream/sink types are
+/// erased to [`BoxedMessageStream`]/[`BoxedMessageSink`] (rather than this
+/// method being generic) so the trait stays dyn-compatible and can be used
+/// as `Arc<dyn SecureTransport>`.
+#[async_trait::async_trait]
+pub trait SecureTransport: Send + Sync {
+    async fn run_handshake(
+        &self,
+        stream: &mut BoxedMessageStream,
+        sink: &mut BoxedMessageSink,
+    ) -> Result<SecureSession, NetworkError>;
+
+    /// The static public key this transport expects `repo_id` to authenticate
+    /// as, if one is already known (e.g. from a prior handshake or an
+    /// out-of-band pinning list). Returning `None` trusts the key presented
+    /// on this first handshake; implementations that maintain a pinned
+    /// registry should override this to reject impersonation of a
+    /// previously-seen `RepoId`.
+    fn known_repo_key(&self, _repo_id: &RepoId) -> Option<VerifyingKey> {
+        None
+    }
+
+    /// Called by [`crate::network_connect`] right after a handshake whose
+    /// `RepoId` had no [`Self::known_repo_key`] entry, with the key it just
+    /// authenticated with. Implementations that maintain a pinned registry
+    /// should override this to record the pin (trust-on-first-use), so the
+    /// *next* handshake claiming this `RepoId` is checked against it rather
+    /// than trusted again. The default does nothing, matching the default
+    /// `known_repo_key`'s "accept anything" behavior.
+    fn observe_repo_key(&self, _repo_id: &RepoId, _public_key: VerifyingKey) {}
+}
+
+/// The default [`SecureTransport`]: a 4-message secret-handshake built on
+/// X25519 key agreement and an ed25519 signature over the exchanged
+/// ephemeral keys, with session keys derived via HKDF over the shared
+/// secret.
+///
+/// Owns an in-memory registry of `RepoId -> VerifyingKey` pins (see
+/// [`Self::pin_repo_key`]): without it, `known_repo_key` would have nothing
+/// to check a first-seen `RepoId` against, and [`SecureSession::bind_repo_id`]
+/// would never actually reject an impersonator, just trust whatever key
+/// shows up on every handshake.
+pub struct SecretHandshakeTransport {
+    keypair: Keypair,
+    pinned_keys: Mutex<HashMap<RepoId, VerifyingKey>>,
+}
+
+impl SecretHandshakeTransport {
+    pub fn new(keypair: Keypair) -> Self {
+        SecretHandshakeTransport {
+            keypair,
+            pinned_keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Pin `repo_id` to `public_key`, so a future handshake claiming that
+    /// `RepoId` is rejected by [`SecureSession::bind_repo_id`] unless it
+    /// authenticates with this exact key. Useful for out-of-band
+    /// provisioning; ordinary trust-on-first-use pinning happens
+    /// automatically via `observe_repo_key` after a `RepoId`'s first
+    /// handshake. Re-pinning a known `RepoId` to a new key (e.g. a
+    /// deliberate key rotation) overwrites the old pin.
+    pub fn pin_repo_key(&self, repo_id: RepoId, public_key: VerifyingKey) {
+        self.pinned_keys.lock().insert(repo_id, public_key);
+    }
+}
+
+#[async_trait::async_trait]
+impl SecureTransport for SecretHandshakeTransport {
+    fn known_repo_key(&self, repo_id: &RepoId) -> Option<VerifyingKey> {
+        self.pinned_keys.lock().get(repo_id).copied()
+    }
+
+    fn observe_repo_key(&self, repo_id: &RepoId, public_key: VerifyingKey) {
+        self.pinned_keys
+            .lock()
+            .entry(repo_id.clone())
+            .or_insert(public_key);
+    }
+
+    async fn run_handshake(
+        &self,
+        stream: &mut BoxedMessageStream,
+        sink: &mut BoxedMessageSink,
+    ) -> Result<SecureSession, NetworkError> {
+        let ephemeral_secret = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral_public = XPublicKey::from(&ephemeral_secret);
+
+        send_handshake(
+            sink,
+            HandshakeMessage::EphemeralKey {
+                ephemeral_public: *ephemeral_public.as_bytes(),
+            },
+        )
+        .await?;
+        let their_ephemeral = match recv_handshake(stream).await? {
+            HandshakeMessage::EphemeralKey { ephemeral_public } => {
+                XPublicKey::from(ephemeral_public)
+            }
+            other => {
+                return Err(NetworkError::Error(format!(
+                    "unexpected handshake message (expecting ephemeral key): {:?}",
+                    other
+                )))
+            }
+        };
+
+        let shared_secret = ephemeral_secret.diffie_hellman(&their_ephemeral);
+
+        let mut transcript = Vec::with_capacity(64);
+        transcript.extend_from_slice(ephemeral_public.as_bytes());
+        transcript.extend_from_slice(their_ephemeral.as_bytes());
+        let signature = self.keypair.signing_key.sign(&transcript);
+        send_handshake(
+            sink,
+            HandshakeMessage::Auth {
+                static_public: self.keypair.public().to_bytes(),
+                signature: signature.to_bytes(),
+            },
+        )
+        .await?;
+
+        let (their_static, their_signature) = match recv_handshake(stream).await? {
+            HandshakeMessage::Auth {
+                static_public,
+                signature,
+            } => (static_public, signature),
+            other => {
+                return Err(NetworkError::Error(format!(
+                    "unexpected handshake message (expecting auth): {:?}",
+                    other
+                )))
+            }
+        };
+        let their_static = VerifyingKey::from_bytes(&their_static)
+            .map_err(|e| NetworkError::Error(format!("invalid remote public key: {}", e)))?;
+        let mut their_transcript = Vec::with_capacity(64);
+        their_transcript.extend_from_slice(their_ephemeral.as_bytes());
+        their_transcript.extend_from_slice(ephemeral_public.as_bytes());
+        their_static
+            .verify(
+                &their_transcript,
+                &ed25519_dalek::Signature::from_bytes(&their_signature),
+            )
+            .map_err(|e| {
+                NetworkError::Error(format!("remote peer failed handshake authentication: {}", e))
+            })?;
+
+        let (send_key, recv_key) = derive_session_keys(
+            shared_secret.as_bytes(),
+            ephemeral_public.as_bytes(),
+            their_ephemeral.as_bytes(),
+        );
+
+        Ok(SecureSession {
+            remote_public: their_static,
+            send_key,
+            recv_key,
+        })
+    }
+}
+
+impl SecureSession {
+    /// Bind this session's authenticated static key to a claimed [`RepoId`],
+    /// rejecting the handshake if the two disagree.
+    pub fn bind_repo_id(
+        &self,
+        claimed: &RepoId,
+        expected_public: &VerifyingKey,
+    ) -> Result<(), NetworkError> {
+        if &self.remote_public != expected_public {
+            return Err(NetworkError::Error(format!(
+                "peer {:?} authenticated with a static key that doesn't match the one expected for it",
+                claimed
+            )));
+        }
+        Ok(())
+    }
+
+    /// Apply this session's keys to a connection's stream/sink so every
+    /// `Message` from here on is boxed in a `Message::Secure` envelope:
+    /// encrypted on the way out with `send_key`, decrypted on the way in
+    /// with `recv_key`.
+    pub fn wrap<Str, Snk>(&self, stream: Str, sink: Snk) -> (SecureStream<Str>, SecureSink<Snk>) {
+        (
+            SecureStream::new(stream, self.recv_key),
+            SecureSink::new(sink, self.send_key),
+        )
+    }
+}
+
+/// Derive direction-separated session keys via HKDF over the shared secret,
+/// with both ephemeral public keys in the HKDF info ordered sender first, so
+/// each side's "send" key matches the other's "recv" key.
+fn derive_session_keys(
+    shared_secret: &[u8],
+    our_ephemeral: &[u8],
+    their_ephemeral: &[u8],
+) -> ([u8; 32], [u8; 32]) {
+    let hk = hkdf::Hkdf::<sha2::Sha256>::new(None, shared_secret);
+    let mut send_key = [0u8; 32];
+    let mut recv_key = [0u8; 32];
+    let mut info = Vec::with_capacity(64);
+    info.extend_from_slice(our_ephemeral);
+    info.extend_from_slice(their_ephemeral);
+    hk.expand(&info, &mut send_key)
+        .expect("32 is a valid HKDF output length");
+    info.clear();
+    info.extend_from_slice(their_ephemeral);
+    info.extend_from_slice(our_ephemeral);
+    hk.expand(&info, &mut recv_key)
+        .expect("32 is a valid HKDF output length");
+    (send_key, recv_key)
+}
+
+async fn send_handshake(sink: &mut BoxedMessageSink, msg: HandshakeMessage) -> Result<(), NetworkError> {
+    let bytes = serde_cbor::to_vec(&msg)
+        .map_err(|e| NetworkError::Error(format!("error encoding handshake message: {}", e)))?;
+    sink.send(Message::Handshake(bytes))
+        .await
+        .map_err(|e| NetworkError::Error(format!("error sending handshake message: {}", e)))
+}
+
+async fn recv_handshake(stream: &mut BoxedMessageStream) -> Result<HandshakeMessage, NetworkError> {
+    match stream.next().await {
+        Some(Ok(Message::Handshake(bytes))) => serde_cbor::from_slice(&bytes)
+            .map_err(|e| NetworkError::Error(format!("error decoding handshake message: {}", e))),
+        Some(Ok(other)) => Err(NetworkError::Error(format!(
+            "unexpected message (expecting handshake): {:?}",
+            other
+        ))),
+        Some(Err(e)) => Err(NetworkError::Error(format!("error receiving: {}", e))),
+        None => Err(NetworkError::Error(
+            "unexpected end of receive stream during secure handshake".to_string(),
+        )),
+    }
+}
+
+fn nonce_from_counter(counter: u64) -> Nonce {
+    // The key is unique per session and per direction, so a strictly
+    // increasing counter is all that's needed to never reuse a nonce.
+    let mut bytes = [0u8; 12];
+    bytes[..8].copy_from_slice(&counter.to_le_bytes());
+    *Nonce::from_slice(&bytes)
+}
+
+/// Decrypts a [`SecureSession`]'s incoming `Message::Secure` envelopes with
+/// its receive key, yielding the plaintext `Message`s underneath.
+pub struct SecureStream<Str> {
+    inner: Str,
+    cipher: ChaCha20Poly1305,
+    recv_counter: u64,
+}
+
+impl<Str> SecureStream<Str> {
+    fn new(inner: Str, recv_key: [u8; 32]) -> Self {
+        SecureStream {
+            inner,
+            cipher: ChaCha20Poly1305::new((&recv_key).into()),
+            recv_counter: 0,
+        }
+    }
+}
+
+impl<Str, RecvErr> Stream for SecureStream<Str>
+where
+    RecvErr: std::error::Error + Send + Sync + 'static,
+    Str: Stream<Item = Result<Message, RecvErr>> + Unpin,
+{
+    type Item = Result<Message, NetworkError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        Pin::new(&mut this.inner).poll_next(cx).map(|item| {
+            item.map(|msg| match msg {
+                Ok(Message::Secure(ciphertext)) => {
+                    let nonce = nonce_from_counter(this.recv_counter);
+                    this.recv_counter += 1;
+                    this.cipher
+                        .decrypt(&nonce, ciphertext.as_slice())
+                        .map_err(|_| {
+                            NetworkError::Error("failed to decrypt incoming message".to_string())
+                        })
+                        .and_then(|plaintext| {
+                            serde_cbor::from_slice(&plaintext).map_err(|e| {
+                                NetworkError::Error(format!(
+                                    "error decoding secured message: {}",
+                                    e
+                                ))
+                            })
+                        })
+                }
+                Ok(other) => Err(NetworkError::Error(format!(
+                    "expected a secured message, got: {:?}",
+                    other
+                ))),
+                Err(e) => Err(NetworkError::Error(format!(
+                    "error receiving secured message: {}",
+                    e
+                ))),
+            })
+        })
+    }
+}
+
+/// Encrypts outgoing `Message`s with a [`SecureSession`]'s send key and
+/// boxes them in a `Message::Secure` envelope before handing them to the
+/// underlying sink.
+pub struct SecureSink<Snk> {
+    inner: Snk,
+    cipher: ChaCha20Poly1305,
+    send_counter: u64,
+}
+
+impl<Snk> SecureSink<Snk> {
+    fn new(inner: Snk, send_key: [u8; 32]) -> Self {
+        SecureSink {
+            inner,
+            cipher: ChaCha20Poly1305::new((&send_key).into()),
+            send_counter: 0,
+        }
+    }
+}
+
+impl<Snk, SendErr> Sink<Message> for SecureSink<Snk>
+where
+    SendErr: std::error::Error + Send + Sync + 'static,
+    Snk: Sink<Message, Error = SendErr> + Unpin,
+{
+    type Error = NetworkError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        Pin::new(&mut this.inner)
+            .poll_ready(cx)
+            .map_err(|e| NetworkError::Error(format!("error sending secured message: {}", e)))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        let plaintext = serde_cbor::to_vec(&item)
+            .map_err(|e| NetworkError::Error(format!("error encoding secured message: {}", e)))?;
+        let nonce = nonce_from_counter(this.send_counter);
+        this.send_counter += 1;
+        let ciphertext = this
+            .cipher
+            .encrypt(&nonce, plaintext.as_slice())
+            .map_err(|_| NetworkError::Error("failed to encrypt outgoing message".to_string()))?;
+        Pin::new(&mut this.inner)
+            .start_send(Message::Secure(ciphertext))
+            .map_err(|e| NetworkError::Error(format!("error sending secured message: {}", e)))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        Pin::new(&mut this.inner)
+            .poll_flush(cx)
+            .map_err(|e| NetworkError::Error(format!("error sending secured message: {}", e)))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        Pin::new(&mut this.inner)
+            .poll_close(cx)
+            .map_err(|e| NetworkError::Error(format!("error sending secured message: {}", e)))
+    }
+}